@@ -1,33 +1,201 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use failure::{self, Error};
 use io_mux::{Mux, TaggedData};
+use is_executable::IsExecutable;
 
+use command_group::{CommandGroup, GroupChild};
+use rand::Rng;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::io::{self, Write};
+use std::os::unix::process::CommandExt;
 use std::os::unix::process::ExitStatusExt;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
 
 use crate::{Opt, Report, Status};
 
+pub fn parse_umask(opt: &Opt) -> u32 {
+    match u32::from_str_radix(&opt.umask, 8) {
+        Ok(mask) => mask,
+        Err(_) => {
+            opt.usage_error(&format!("invalid umask: {}", opt.umask));
+            unreachable!()
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref ACTIVE_GROUPS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+}
+
+static SIGNAL_FORWARDING: Once = Once::new();
+
+/// 0 while run-parts hasn't received SIGINT/SIGTERM, otherwise the signal that did it.
+static ABORT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// true once run-parts has received SIGINT/SIGTERM and forwarded it to running scripts.
+/// Callers consult this to stop launching further scripts, the same way `--exit-on-error`
+/// does for a failing script.
+pub fn aborted() -> bool {
+    ABORT_SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+/// the exit code run-parts itself should report once aborted, consistent with the existing
+/// signal-to-exit-code mapping (128 + signal number).
+pub fn abort_exit_code() -> Option<exitcode::ExitCode> {
+    match ABORT_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(128 + sig),
+    }
+}
+
+/// forwards SIGINT/SIGTERM received by run-parts to the process group of every script
+/// currently running, so Ctrl-C or a service manager's SIGTERM can't leave a script (or
+/// anything it backgrounded) behind as an orphan. Also marks run-parts as aborted so the
+/// run loops stop launching further scripts and the process exits non-zero once the
+/// scripts it already started have been reaped.
+fn ensure_signal_forwarding() {
+    SIGNAL_FORWARDING.call_once(|| {
+        let mut signals =
+            Signals::new(&[SIGINT, SIGTERM]).expect("failed to install signal handlers");
+        std::thread::spawn(move || {
+            for sig in signals.forever() {
+                ABORT_SIGNAL.store(sig, Ordering::SeqCst);
+                for &pgid in ACTIVE_GROUPS.lock().unwrap().iter() {
+                    unsafe {
+                        libc::kill(-pgid, sig);
+                    }
+                }
+            }
+        });
+    });
+}
+
+fn register_group(pgid: libc::pid_t) {
+    ACTIVE_GROUPS.lock().unwrap().push(pgid);
+}
+
+fn unregister_group(pgid: libc::pid_t) {
+    ACTIVE_GROUPS.lock().unwrap().retain(|&p| p != pgid);
+}
+
+/// builds the `Command` for `fp`, wiring up the umask and, with `--new-session`, a fresh
+/// session via `setsid` in the child before it execs.
+///
+/// `group_spawn` (see `spawn_group`) runs its own `setpgid(0, 0)` in a `pre_exec` closure
+/// registered after this one, to make the child the leader of its own process group.
+/// `setsid` already makes the calling process the leader of a new session *and* of a new
+/// process group with the same id as its own pid, so by the time `setpgid(0, 0)` runs it is
+/// a no-op: POSIX permits a process to set its own process group id to its own pid even as a
+/// session leader, it only rejects moving a session leader into a *different* group.
+fn build_command(opt: &Opt, fp: &Path) -> Command {
+    let mask = parse_umask(opt);
+    let new_session = opt.new_session;
+    let mut command = Command::new(fp.to_str().unwrap());
+    command.args(&opt.arg);
+    unsafe {
+        command.pre_exec(move || {
+            libc::umask(mask);
+            Ok(())
+        });
+        if new_session {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+/// spawns `command` as the leader of its own process group (via the `command-group` crate),
+/// registers that group so a forwarded SIGINT/SIGTERM reaches it, and arms `--timeout` if set.
+/// Returns the child alongside a `done` flag the caller must set once it has reaped the child,
+/// and a `timed_out` flag that is set if the timeout fired.
+fn spawn_group(
+    opt: &Opt,
+    command: &mut Command,
+) -> io::Result<(GroupChild, Arc<AtomicBool>, Arc<AtomicBool>)> {
+    ensure_signal_forwarding();
+    let child = command.group_spawn()?;
+    let pgid = child.id() as libc::pid_t;
+    register_group(pgid);
+    let done = Arc::new(AtomicBool::new(false));
+    let timed_out = Arc::new(AtomicBool::new(false));
+    if let Some(timeout) = opt.timeout {
+        spawn_timeout_watcher(
+            pgid,
+            Duration::from_secs(timeout),
+            done.clone(),
+            timed_out.clone(),
+        );
+    }
+    Ok((child, done, timed_out))
+}
+
+/// grace period between escalating a timed-out script from SIGTERM to SIGKILL.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(2);
+
+/// waits for `timeout` to elapse; if the script hasn't finished by then, SIGTERMs its whole
+/// process group, waits a grace period, then SIGKILLs it if it is still alive. `done` is set by
+/// the reaping thread once the child has actually exited, so a script that finishes in time
+/// never gets signaled.
+fn spawn_timeout_watcher(
+    pgid: libc::pid_t,
+    timeout: Duration,
+    done: Arc<AtomicBool>,
+    timed_out: Arc<AtomicBool>,
+) {
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        if done.load(Ordering::SeqCst) {
+            return;
+        }
+        timed_out.store(true, Ordering::SeqCst);
+        unsafe {
+            libc::kill(-pgid, libc::SIGTERM);
+        }
+        std::thread::sleep(TIMEOUT_GRACE);
+        if !done.load(Ordering::SeqCst) {
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+    });
+}
+
 pub fn exec(opt: &Opt, fp: &Path, status: &mut Status) -> Result<(), Error> {
     let mut mux = Mux::new()?;
     let mut report = Report::new(opt, fp);
-    let mut child = Command::new(fp.to_str().unwrap())
-        .args(&opt.arg)
+    let mut command = build_command(opt, fp);
+    command
         .stdout(mux.make_untagged_sender()?)
-        .stderr(mux.make_tagged_sender("e")?)
-        .spawn()?;
+        .stderr(mux.make_tagged_sender("e")?);
+    let (mut child, done, timed_out) = spawn_group(opt, &mut command)?;
+    let pgid = child.id() as libc::pid_t;
     let mut done_sender = mux.make_tagged_sender("d")?;
-    std::thread::spawn(move || match child.wait() {
-        Ok(status) => {
-            let exit_code = if let Some(code) = status.code() {
-                code as u8
-            } else {
-                status.signal().unwrap() as u8 + 128
-            };
-            let _ = done_sender.write_all(&[exit_code]);
-        }
-        Err(e) => {
-            let _ = writeln!(done_sender, "Error: {:?}", e);
+    std::thread::spawn(move || {
+        let result = child.wait();
+        done.store(true, Ordering::SeqCst);
+        unregister_group(pgid);
+        match result {
+            Ok(status) => {
+                let exit_code = if let Some(code) = status.code() {
+                    code as u8
+                } else {
+                    status.signal().unwrap() as u8 + 128
+                };
+                let _ = done_sender.write_all(&[exit_code]);
+            }
+            Err(e) => {
+                let _ = writeln!(done_sender, "Error: {:?}", e);
+            }
         }
     });
 
@@ -42,6 +210,7 @@ pub fn exec(opt: &Opt, fp: &Path, status: &mut Status) -> Result<(), Error> {
         match (tag.as_deref(), data) {
             (Some("d"), &[exit_code]) => {
                 status.exit_code = exit_code as i32;
+                status.timed_out = timed_out.load(Ordering::SeqCst);
                 break;
             }
             (Some("d"), error) => {
@@ -63,3 +232,213 @@ fn write(w: &mut dyn Write, data: &[u8], report: Option<&String>) {
     }
     w.write_all(data).unwrap();
 }
+
+/// arms the `--randomize` start delay for slot `idx` without blocking the calling thread:
+/// a helper thread sleeps for a random duration in `[0, opt.randomize]` seconds, then signals
+/// readiness on the shared `mux` under an `r<idx>` tag, the same way `d<idx>` reports
+/// completion. The caller keeps draining `mux` in the meantime, so other in-flight scripts'
+/// output is never held up by the delay.
+fn schedule_launch(opt: &Opt, idx: usize, mux: &mut Mux) -> Result<(), Error> {
+    let delay = rand::thread_rng().gen_range(0..=opt.randomize);
+    let mut ready_sender = mux.make_tagged_sender(&format!("r{}", idx))?;
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_secs(delay));
+        let _ = ready_sender.write_all(&[0]);
+    });
+    Ok(())
+}
+
+/// spawns `fp` tagging its stdout/stderr/completion on `mux` with `o<idx>`/`e<idx>`/`d<idx>`
+/// so several scripts can share one `Mux` without their output interleaving.
+fn spawn_tagged(
+    opt: &Opt,
+    fp: &Path,
+    idx: usize,
+    mux: &mut Mux,
+) -> Result<(Report, Arc<AtomicBool>), Error> {
+    let report = Report::new(opt, fp);
+    let mut command = build_command(opt, fp);
+    command
+        .stdout(mux.make_tagged_sender(&format!("o{}", idx))?)
+        .stderr(mux.make_tagged_sender(&format!("e{}", idx))?);
+    let (mut child, done, timed_out) = spawn_group(opt, &mut command)?;
+    let pgid = child.id() as libc::pid_t;
+    let mut done_sender = mux.make_tagged_sender(&format!("d{}", idx))?;
+    std::thread::spawn(move || {
+        let result = child.wait();
+        done.store(true, Ordering::SeqCst);
+        unregister_group(pgid);
+        match result {
+            Ok(status) => {
+                let exit_code = if let Some(code) = status.code() {
+                    code as u8
+                } else {
+                    status.signal().unwrap() as u8 + 128
+                };
+                let _ = done_sender.write_all(&[exit_code]);
+            }
+            Err(e) => {
+                let _ = writeln!(done_sender, "Error: {:?}", e);
+            }
+        }
+    });
+    Ok((report, timed_out))
+}
+
+struct Slot {
+    fp: PathBuf,
+    report: Report,
+    timed_out: Arc<AtomicBool>,
+    out_buf: Vec<u8>,
+    err_buf: Vec<u8>,
+}
+
+/// runs `files` with up to `opt.parallel` scripts in flight at once. Each script's output is
+/// buffered behind its own `o<idx>`/`e<idx>` tag on a shared `Mux` and flushed atomically once
+/// its `d<idx>` tag reports completion, so concurrent scripts never interleave their lines.
+/// `--exit-on-error` stops launching new scripts as soon as one fails, but already-running
+/// scripts are left to finish.
+pub fn exec_parallel(opt: &Opt, files: &[&PathBuf]) -> Result<Status, Error> {
+    let mut mux = Mux::new()?;
+    let mut overall = Status::default();
+    let mut pending = files.iter();
+    let mut slots: HashMap<usize, Slot> = HashMap::new();
+    let mut launching: HashMap<usize, PathBuf> = HashMap::new();
+    let mut next_idx: usize = 0;
+    let mut stopped = false;
+
+    loop {
+        while !stopped && !aborted() && slots.len() + launching.len() < opt.parallel {
+            let fp = match pending.next() {
+                Some(fp) => fp,
+                None => break,
+            };
+            if !fp.is_executable() {
+                continue;
+            }
+            let idx = next_idx;
+            next_idx += 1;
+            if opt.randomize > 0 {
+                // schedule the launch for later, through the mux itself (an `r<idx>` tag),
+                // instead of sleeping here: this thread also drains the output of every
+                // already-running script, and blocking it would stall that draining and
+                // serialize launches, defeating --parallel.
+                schedule_launch(opt, idx, &mut mux)?;
+                launching.insert(idx, fp.to_path_buf());
+                continue;
+            }
+            if opt.verbose {
+                eprintln!(
+                    "run-parts: executing {} {}",
+                    fp.to_str().unwrap(),
+                    &opt.arg.join(" ")
+                );
+            }
+            let (report, timed_out) = spawn_tagged(opt, fp, idx, &mut mux)?;
+            slots.insert(
+                idx,
+                Slot {
+                    fp: fp.to_path_buf(),
+                    report,
+                    timed_out,
+                    out_buf: Vec::new(),
+                    err_buf: Vec::new(),
+                },
+            );
+        }
+        if slots.is_empty() && launching.is_empty() {
+            break;
+        }
+
+        let TaggedData { tag, data } = mux.read()?;
+        let tag = match tag {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let (kind, idx) = tag.split_at(1);
+        let idx: usize = match idx.parse() {
+            Ok(idx) => idx,
+            Err(_) => continue,
+        };
+        match kind {
+            "r" => {
+                let fp = match launching.remove(&idx) {
+                    Some(fp) => fp,
+                    None => continue,
+                };
+                if stopped || aborted() {
+                    continue;
+                }
+                if opt.verbose {
+                    eprintln!(
+                        "run-parts: executing {} {}",
+                        fp.to_str().unwrap(),
+                        &opt.arg.join(" ")
+                    );
+                }
+                let (report, timed_out) = spawn_tagged(opt, &fp, idx, &mut mux)?;
+                slots.insert(
+                    idx,
+                    Slot {
+                        fp,
+                        report,
+                        timed_out,
+                        out_buf: Vec::new(),
+                        err_buf: Vec::new(),
+                    },
+                );
+            }
+            "o" => {
+                if let Some(slot) = slots.get_mut(&idx) {
+                    slot.out_buf.extend_from_slice(data);
+                }
+            }
+            "e" => {
+                if let Some(slot) = slots.get_mut(&idx) {
+                    slot.err_buf.extend_from_slice(data);
+                }
+            }
+            "d" => {
+                let mut slot = match slots.remove(&idx) {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                let exit_code = match data {
+                    &[exit_code] => exit_code as i32,
+                    error => {
+                        io::stderr().write_all(error)?;
+                        exitcode::SOFTWARE
+                    }
+                };
+                // only consult the Report accessors when that stream actually produced
+                // output, otherwise the first (empty) call consumes `Report.used` and the
+                // stream that did produce output loses its name prefix.
+                if !slot.out_buf.is_empty() {
+                    write(&mut io::stdout().lock(), &slot.out_buf, slot.report.out_report());
+                }
+                if !slot.err_buf.is_empty() {
+                    write(&mut io::stderr().lock(), &slot.err_buf, slot.report.err_report());
+                }
+                if (opt.report || opt.verbose) && exit_code != exitcode::OK {
+                    if slot.timed_out.load(Ordering::SeqCst) {
+                        eprintln!("run-parts: {} timed out", slot.fp.to_str().unwrap());
+                    } else {
+                        eprintln!(
+                            "run-parts: {} exited with return code {}",
+                            slot.fp.to_str().unwrap(),
+                            exit_code
+                        );
+                    }
+                }
+                if exit_code > overall.exit_code {
+                    overall.exit_code = exit_code;
+                }
+                if opt.exit_on_error && exit_code != exitcode::OK {
+                    stopped = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(overall)
+}
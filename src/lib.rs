@@ -35,10 +35,40 @@ pub struct Opt {
     #[structopt(long)]
     pub reverse: bool,
 
-    /// exit as soon as a script returns with a non-zero exit code.
+    /// recurse into subdirectories, applying the same filename filters at every level,
+    /// instead of only looking at the immediate contents of DIRECTORY (the default).
+    #[structopt(long)]
+    pub recursive: bool,
+
+    /// exit as soon as a script returns with a non-zero exit code. In --parallel mode this
+    /// stops launching new scripts, but scripts which are already running are allowed to
+    /// finish.
     #[structopt(long)]
     pub exit_on_error: bool,
 
+    /// run up to N scripts at the same time instead of one after another. Each script's
+    /// output is buffered and printed as a whole once the script finishes, so concurrent
+    /// scripts never interleave their output. Defaults to 1 (sequential execution).
+    #[structopt(short = "j", long, default_value = "1")]
+    pub parallel: usize,
+
+    /// run each script in a new session (via setsid), detaching it from run-parts'
+    /// controlling terminal in addition to giving it its own process group.
+    #[structopt(long)]
+    pub new_session: bool,
+
+    /// kill a script (and its whole process group) if it hasn't finished after SECONDS.
+    /// It is sent SIGTERM first, then SIGKILL if it is still alive after a short grace period.
+    #[structopt(long)]
+    pub timeout: Option<u64>,
+
+    /// wait a random number of seconds, uniformly chosen between 0 and MAX_SECONDS, before
+    /// running each script. Useful to spread out load when many hosts run the same script
+    /// directory on the same schedule. Skipped in --list/--test modes, when MAX_SECONDS is 0
+    /// (the default), and once a previous script has already failed with --exit-on-error.
+    #[structopt(long, default_value = "0")]
+    pub randomize: u64,
+
     /// sets the umask to umask before running the scripts. umask should be specified in
     /// octal. By default the umask is set to 022.
     #[structopt(long, default_value = "022")]
@@ -84,11 +114,13 @@ impl Opt {
 #[derive(Default)]
 pub struct Status {
     pub exit_code: exitcode::ExitCode,
+    pub timed_out: bool,
 }
 
 impl Status {
     pub fn reset(&mut self) {
         self.exit_code = exitcode::OK;
+        self.timed_out = false;
     }
 }
 
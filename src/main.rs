@@ -1,23 +1,48 @@
 use failure::{self, Error};
 use is_executable::IsExecutable;
+use rand::Rng;
 
+use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 use std::result::Result;
+use std::time::Duration;
 use structopt::StructOpt;
 
 use run_parts::exec::*;
 use run_parts::filter::*;
 use run_parts::*;
 
-fn find_files(opt: &Opt, dir: &Path) -> Result<Vec<PathBuf>, Error> {
-    let mut result: Vec<PathBuf> = [].to_vec();
+/// collects `dir`'s entries into `result`, descending into subdirectories when
+/// `opt.recursive` is set. `visited` holds the canonical path of every directory already
+/// walked, so a symlink cycle is skipped instead of recursing forever.
+fn collect_files(
+    opt: &Opt,
+    dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+    result: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    if !visited.insert(fs::canonicalize(dir)?) {
+        return Ok(());
+    }
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
-        result.push(entry.path());
+        let path = entry.path();
+        if opt.recursive && path.is_dir() {
+            collect_files(opt, &path, visited, result)?;
+        } else {
+            result.push(path);
+        }
     }
+    Ok(())
+}
+
+fn find_files(opt: &Opt, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut result: Vec<PathBuf> = [].to_vec();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    collect_files(opt, dir, &mut visited, &mut result)?;
     result.sort();
     if opt.reverse {
         result.reverse();
@@ -25,23 +50,26 @@ fn find_files(opt: &Opt, dir: &Path) -> Result<Vec<PathBuf>, Error> {
     Ok(result)
 }
 
-fn act_on_file(opt: &Opt, fp: &Path, status: &mut Status) {
-    if opt.exit_on_error && status.exit_code != exitcode::OK {
-        return;
+fn act_on_file(opt: &Opt, fp: &Path, status: &mut Status) -> Result<(), Error> {
+    if (opt.exit_on_error && status.exit_code != exitcode::OK) || aborted() {
+        return Ok(());
     }
     status.reset();
     if opt.list {
         println!("{} {}", &fp.to_str().unwrap(), &opt.arg.join(" "));
-        return;
+        return Ok(());
     }
     if !fp.is_executable() {
-        return;
+        return Ok(());
     }
     if opt.test {
         println!("{} {}", &fp.to_str().unwrap(), &opt.arg.join(" "));
-        return;
+        return Ok(());
+    }
+    if opt.randomize > 0 {
+        let delay = rand::thread_rng().gen_range(0..=opt.randomize);
+        std::thread::sleep(Duration::from_secs(delay));
     }
-    // TODO - implement random sleep
     if opt.verbose {
         eprintln!(
             "run-parts: executing {} {}",
@@ -49,23 +77,37 @@ fn act_on_file(opt: &Opt, fp: &Path, status: &mut Status) {
             &opt.arg.join(" ")
         );
     }
-    // TODO - implement umask
-    exec(opt, fp, status).unwrap();
+    exec(opt, fp, status)?;
     if (opt.report || opt.verbose) && status.exit_code != exitcode::OK {
-        eprintln!(
-            "run-parts: {} exited with return code {}",
-            &fp.to_str().unwrap(),
-            status.exit_code
-        );
+        if status.timed_out {
+            eprintln!("run-parts: {} timed out", &fp.to_str().unwrap());
+        } else {
+            eprintln!(
+                "run-parts: {} exited with return code {}",
+                &fp.to_str().unwrap(),
+                status.exit_code
+            );
+        }
     }
+    Ok(())
 }
 
 fn run(opt: &Opt) -> Result<Status, Error> {
     let files = find_files(opt, &opt.dir)?;
     let files_to_process: Vec<&PathBuf> = files.iter().filter(|fp| filter_file(opt, fp)).collect();
-    let mut status: Status = Status::default();
-    for entry in files_to_process {
-        act_on_file(opt, entry, &mut status);
+    let mut status = if opt.parallel > 1 && !opt.list && !opt.test {
+        exec_parallel(opt, &files_to_process)?
+    } else {
+        let mut status: Status = Status::default();
+        for entry in files_to_process {
+            act_on_file(opt, entry, &mut status)?;
+        }
+        status
+    };
+    // a forwarded SIGINT/SIGTERM should make run-parts exit non-zero even if every script
+    // that was already running happened to exit 0 before it was reaped.
+    if let Some(exit_code) = abort_exit_code() {
+        status.exit_code = exit_code;
     }
     Ok(status)
 }
@@ -76,6 +118,9 @@ fn main() {
     if opt.list && opt.test {
         opt.usage_error("--list and --test cannot be used together");
     }
+    // validate --umask up front so a bad value fails fast, the same way in every mode,
+    // instead of only once a script is about to be spawned.
+    parse_umask(&opt);
     match run(&opt) {
         Ok(status) => process::exit(status.exit_code),
         Err(e) => {